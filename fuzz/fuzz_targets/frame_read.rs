@@ -0,0 +1,15 @@
+#![no_main]
+extern crate libfuzzer_sys;
+extern crate rust_chat;
+
+use rust_chat::frame::{Role, WebSocketFrame, DEFAULT_MAX_PAYLOAD_LEN};
+use std::io::Cursor;
+
+// Feeds arbitrary bytes into the frame parser from both roles. It must
+// never panic (the `unsafe set_len` bug this replaced could read
+// uninitialized memory) and must never allocate past `DEFAULT_MAX_PAYLOAD_LEN`
+// regardless of what length the header claims.
+libfuzzer_sys::fuzz_target!(|data: &[u8]| {
+    let _ = WebSocketFrame::read(&mut Cursor::new(data), DEFAULT_MAX_PAYLOAD_LEN, Role::Server);
+    let _ = WebSocketFrame::read(&mut Cursor::new(data), DEFAULT_MAX_PAYLOAD_LEN, Role::Client);
+});
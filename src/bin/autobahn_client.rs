@@ -0,0 +1,152 @@
+// Conformance harness: drives this crate's frame codec against the Autobahn
+// TestSuite fuzzing server (https://github.com/crossbario/autobahn-testsuite)
+// as a WebSocket client, exercising fragmentation, invalid UTF-8, oversized
+// control frames and other edge cases that a hand-rolled client/server
+// rarely hits on its own.
+//
+// Usage: start `wstest -m fuzzingserver`, then run this binary against it
+// (defaults to 127.0.0.1:9001, override with AUTOBAHN_HOST).
+extern crate rust_chat;
+extern crate rand;
+extern crate rustc_serialize;
+
+use rust_chat::frame::{CloseCode, Role, WebSocketFrame, DEFAULT_MAX_PAYLOAD_LEN};
+use rust_chat::message::{Message, MessageReassembler};
+
+use rand::Rng;
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+const AGENT: &'static str = "rust_chat";
+
+fn autobahn_host() -> String {
+    std::env::var("AUTOBAHN_HOST").unwrap_or_else(|_| "127.0.0.1:9001".to_string())
+}
+
+fn handshake_key() -> String {
+    let key: [u8; 16] = rand::thread_rng().gen();
+    key.to_base64(STANDARD)
+}
+
+fn connect(host: &str, path: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(host).unwrap();
+
+    let request = format!("GET {} HTTP/1.1\r\n\
+                            Host: {}\r\n\
+                            Upgrade: websocket\r\n\
+                            Connection: Upgrade\r\n\
+                            Sec-WebSocket-Key: {}\r\n\
+                            Sec-WebSocket-Version: 13\r\n\r\n",
+                           path, host, handshake_key());
+    stream.write_all(request.as_bytes()).unwrap();
+    skip_handshake_response(&mut stream);
+    stream
+}
+
+// The handshake response ends at the first blank line; everything after it
+// is already WebSocket frame data and must be left for the frame codec.
+fn skip_handshake_response(stream: &mut TcpStream) {
+    let mut byte = [0u8; 1];
+    let mut matched = 0;
+
+    while matched < 4 {
+        stream.read_exact(&mut byte).unwrap();
+        matched = match (matched, byte[0]) {
+            (0, b'\r') => 1,
+            (1, b'\n') => 2,
+            (2, b'\r') => 3,
+            (3, b'\n') => 4,
+            _ => 0
+        };
+    }
+}
+
+fn get_case_count(host: &str) -> u32 {
+    let mut stream = connect(host, "/getCaseCount");
+    let mut reassembler = MessageReassembler::new();
+
+    loop {
+        let frame = match WebSocketFrame::read(&mut stream, DEFAULT_MAX_PAYLOAD_LEN, Role::Client) {
+            Ok(frame) => frame,
+            Err(_) => return 0
+        };
+
+        if let Ok(Some(Message::Text(count))) = reassembler.add(frame) {
+            return count.trim().parse().unwrap_or(0);
+        }
+    }
+}
+
+// Echoes every text/binary message back verbatim and answers a close frame
+// with our own, then returns once the server has ended the case.
+fn run_case(host: &str, case: u32) {
+    let path = format!("/runCase?case={}&agent={}", case, AGENT);
+    let mut stream = connect(host, &path);
+    let mut reassembler = MessageReassembler::new();
+
+    loop {
+        let frame = match WebSocketFrame::read(&mut stream, DEFAULT_MAX_PAYLOAD_LEN, Role::Client) {
+            Ok(frame) => frame,
+            Err(_) => return
+        };
+
+        match reassembler.add(frame) {
+            Ok(Some(Message::Text(text))) => {
+                let reply = WebSocketFrame::text(&text, Role::Client);
+                if reply.write(&mut stream).is_err() {
+                    return;
+                }
+            },
+            Ok(Some(Message::Binary(data))) => {
+                let reply = WebSocketFrame::binary(&data, Role::Client);
+                if reply.write(&mut stream).is_err() {
+                    return;
+                }
+            },
+            Ok(Some(Message::Ping(data))) => {
+                let reply = WebSocketFrame::pong(&data, Role::Client);
+                if reply.write(&mut stream).is_err() {
+                    return;
+                }
+            },
+            Ok(Some(Message::Close(close))) => {
+                let reply = match close {
+                    Some((code, _reason)) => WebSocketFrame::close_with_code(code, "", Role::Client),
+                    None => WebSocketFrame::close(CloseCode::Normal, "", Role::Client)
+                };
+                let _ = reply.write(&mut stream);
+                return;
+            },
+            Ok(Some(_)) | Ok(None) => {},
+            Err(_) => return
+        }
+    }
+}
+
+fn update_reports(host: &str) {
+    let path = format!("/updateReports?agent={}", AGENT);
+    let mut stream = connect(host, &path);
+
+    // The server closes the connection once the report has been written;
+    // just drain it.
+    let mut buf = [0u8; 256];
+    while let Ok(n) = stream.read(&mut buf) {
+        if n == 0 {
+            break;
+        }
+    }
+}
+
+fn main() {
+    let host = autobahn_host();
+    let cases = get_case_count(&host);
+    println!("Autobahn: running {} cases against {}", cases, host);
+
+    for case in 1..(cases + 1) {
+        run_case(&host, case);
+    }
+
+    update_reports(&host);
+    println!("Autobahn: report generated");
+}
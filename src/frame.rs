@@ -1,17 +1,60 @@
 use std::io;
 use std::io::Result as IOResult;
 use std::io::{Read, Write};
-use std::error::Error;
 use std::u16;
 
 use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+use rand::Rng;
 
 const FRAME_LEN_U16: u8 = 126;
 const FRAME_LEN_U64: u8 = 127;
 
-#[derive(Debug, Clone, PartialEq)]
-#[allow(dead_code)]
-enum OpCode {
+// Which end of the connection a frame is being read from or written for.
+// Per RFC 6455 section 5.1, client-to-server frames are always masked and
+// server-to-client frames are never masked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Server,
+    Client
+}
+
+// Default cap on a single frame's payload, used unless a server configures
+// its own `max_payload_len`. Keeps a malicious/buggy peer from driving a
+// huge allocation via an oversized length field.
+pub const DEFAULT_MAX_PAYLOAD_LEN: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(io::Error),
+    PayloadTooLarge,
+    // The frame's masked bit didn't match what the peer's role requires.
+    MaskMismatch,
+    // A reserved bit (RSV1-3) was set without an extension having negotiated
+    // a meaning for it. Per RFC 6455 section 5.2, the connection must fail.
+    ReservedBitsSet
+}
+
+impl From<io::Error> for FrameError {
+    fn from(err: io::Error) -> FrameError {
+        FrameError::Io(err)
+    }
+}
+
+impl FrameError {
+    // The close code a server should send back when it has to abort the
+    // connection because of this error.
+    pub fn close_code(&self) -> CloseCode {
+        match *self {
+            FrameError::PayloadTooLarge => CloseCode::MessageTooBig,
+            FrameError::MaskMismatch | FrameError::ReservedBitsSet | FrameError::Io(_) =>
+                CloseCode::ProtocolError
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpCode {
+    Continuation = 0,
     TextFrame = 1,
     BinaryFrame = 2,
     ConnectionClose = 8,
@@ -19,6 +62,65 @@ enum OpCode {
     Pong = 0xA
 }
 
+impl OpCode {
+    pub fn from_u8(opcode: u8) -> Option<OpCode> {
+        match opcode {
+            0 => Some(OpCode::Continuation),
+            1 => Some(OpCode::TextFrame),
+            2 => Some(OpCode::BinaryFrame),
+            8 => Some(OpCode::ConnectionClose),
+            9 => Some(OpCode::Ping),
+            0xA => Some(OpCode::Pong),
+            _ => None
+        }
+    }
+
+    // Control frames (close/ping/pong) may never be fragmented.
+    pub fn is_control(&self) -> bool {
+        match *self {
+            OpCode::ConnectionClose | OpCode::Ping | OpCode::Pong => true,
+            _ => false
+        }
+    }
+}
+
+// RFC 6455 section 7.4.1 status codes used when closing a connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloseCode {
+    Normal = 1000,
+    ProtocolError = 1002,
+    InvalidData = 1003,
+    PolicyViolation = 1008,
+    MessageTooBig = 1009,
+    InternalError = 1011
+}
+
+impl CloseCode {
+    pub fn from_u16(code: u16) -> Option<CloseCode> {
+        match code {
+            1000 => Some(CloseCode::Normal),
+            1002 => Some(CloseCode::ProtocolError),
+            1003 => Some(CloseCode::InvalidData),
+            1008 => Some(CloseCode::PolicyViolation),
+            1009 => Some(CloseCode::MessageTooBig),
+            1011 => Some(CloseCode::InternalError),
+            _ => None
+        }
+    }
+
+    // Whether `code` is legal to send or receive on the wire: the codes RFC
+    // 6455 section 7.4.1 defines for endpoint use (skipping 1004-1006/1015,
+    // which are reserved for local use and must never appear in a close
+    // frame), plus the 3000-4999 ranges reserved for libraries/applications.
+    pub fn is_valid(code: u16) -> bool {
+        match code {
+            1000...1003 | 1007...1011 => true,
+            3000...4999 => true,
+            _ => false
+        }
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct WebSocketFrameHeader {
@@ -32,11 +134,11 @@ pub struct WebSocketFrameHeader {
 }
 
 impl WebSocketFrameHeader {
-    fn new_header(len: usize, opcode: u8) -> WebSocketFrameHeader {
+    fn new_header(len: usize, opcode: u8, masked: bool) -> WebSocketFrameHeader {
         WebSocketFrameHeader {
             fin: true,
             rsv1: false, rsv2: false, rsv3: false,
-            masked: false,
+            masked: masked,
             payload_length: Self::determine_len(len),
             opcode: opcode
         }
@@ -60,27 +162,63 @@ pub struct WebSocketFrame {
     pub payload: Vec<u8>
 }
 
-impl<'a> From<&'a [u8]> for WebSocketFrame {
-    fn from(payload: &[u8]) -> WebSocketFrame {
-        WebSocketFrame {
-            header: WebSocketFrameHeader::new_header(payload.len(), OpCode::BinaryFrame as u8),
-            payload: Vec::from(payload),
-            mask: None
-        }
+impl WebSocketFrame {
+    pub fn text(payload: &str, role: Role) -> WebSocketFrame {
+        Self::with_payload(OpCode::TextFrame, Vec::from(payload), role)
+    }
+
+    pub fn binary(payload: &[u8], role: Role) -> WebSocketFrame {
+        Self::with_payload(OpCode::BinaryFrame, Vec::from(payload), role)
     }
-}
 
-impl<'a> From<&'a str> for WebSocketFrame {
-    fn from(payload: &str) -> WebSocketFrame {
+    // A pong frame, per RFC 6455 section 5.5.3 must carry back the ping's
+    // payload unchanged.
+    pub fn pong(payload: &[u8], role: Role) -> WebSocketFrame {
+        Self::with_payload(OpCode::Pong, Vec::from(payload), role)
+    }
+
+    // Builds a close frame carrying the given status code and an optional
+    // UTF-8 reason, per RFC 6455 section 5.5.1.
+    pub fn close(code: CloseCode, reason: &str, role: Role) -> WebSocketFrame {
+        Self::close_with_code(code as u16, reason, role)
+    }
+
+    // Like `close`, but takes a raw status code. Used to echo back a code a
+    // peer sent us, which `CloseCode` (an enum of codes *we* send) can't
+    // represent directly.
+    pub fn close_with_code(code: u16, reason: &str, role: Role) -> WebSocketFrame {
+        let mut payload = Vec::with_capacity(2 + reason.len());
+        payload.write_u16::<BigEndian>(code).unwrap();
+        payload.extend_from_slice(reason.as_bytes());
+
+        Self::with_payload(OpCode::ConnectionClose, payload, role)
+    }
+
+    fn with_payload(opcode: OpCode, payload: Vec<u8>, role: Role) -> WebSocketFrame {
+        let mask = match role {
+            Role::Client => Some(Self::generate_mask()),
+            Role::Server => None
+        };
+
         WebSocketFrame {
-            header: WebSocketFrameHeader::new_header(payload.len(), OpCode::TextFrame as u8),
-            payload: Vec::from(payload),
-            mask: None
+            header: WebSocketFrameHeader::new_header(payload.len(), opcode as u8, mask.is_some()),
+            payload: payload,
+            mask: mask
         }
     }
-}
 
-impl WebSocketFrame {
+    fn generate_mask() -> [u8; 4] {
+        rand::thread_rng().gen::<[u8; 4]>()
+    }
+
+    pub fn opcode(&self) -> Option<OpCode> {
+        OpCode::from_u8(self.header.opcode)
+    }
+
+    pub fn is_fin(&self) -> bool {
+        self.header.fin
+    }
+
     pub fn write<W: Write>(&self, output: &mut W) -> IOResult<()> {
         let hdr = Self::serialize_header(&self.header);
         try!(output.write_u16::<BigEndian>(hdr));
@@ -91,18 +229,41 @@ impl WebSocketFrame {
             _ => {}
         }
 
-        try!(output.write(&self.payload));
+        match self.mask {
+            Some(mask) => {
+                try!(output.write_all(&mask));
+                let mut masked_payload = self.payload.clone();
+                Self::apply_mask(mask, &mut masked_payload);
+                try!(output.write_all(&masked_payload));
+            },
+            None => try!(output.write_all(&self.payload))
+        }
+
         Ok(())
     }
 
-    pub fn read<R: Read>(input: &mut R) -> IOResult<WebSocketFrame> {
+    // `role` is the role of the connection *we* are playing: a `Server`
+    // expects masked frames (from a client), a `Client` expects unmasked
+    // frames (from a server).
+    pub fn read<R: Read>(input: &mut R, max_payload_len: usize, role: Role) -> Result<WebSocketFrame, FrameError> {
         let buf = try!(input.read_u16::<BigEndian>());
         let header = Self::parse_header(buf);
 
+        if header.rsv1 || header.rsv2 || header.rsv3 {
+            return Err(FrameError::ReservedBitsSet);
+        }
+
+        if header.masked != (role == Role::Server) {
+            return Err(FrameError::MaskMismatch);
+        }
+
         let len = try!(Self::read_length(header.payload_length, input));
+        if len > max_payload_len {
+            return Err(FrameError::PayloadTooLarge);
+        }
+
         let mask_key = if header.masked {
-            let mask = try!(Self::read_mask(input));
-            Some(mask)
+            Some(try!(Self::read_mask(input)))
         } else {
             None
         };
@@ -153,14 +314,23 @@ impl WebSocketFrame {
 
     fn read_mask<R: Read>(input: &mut R) -> IOResult<[u8; 4]> {
         let mut buf = [0; 4];
-        try!(input.read(&mut buf));
+        try!(input.read_exact(&mut buf));
         Ok(buf)
     }
 
     fn read_payload<R: Read>(payload_len: usize, input: &mut R) -> IOResult<Vec<u8>> {
-        let mut payload: Vec<u8> = Vec::with_capacity(payload_len);
-        unsafe { payload.set_len(payload_len) };
-        try!(input.read(&mut payload));
+        let mut payload: Vec<u8> = vec![0; payload_len];
+        let mut read = 0;
+
+        while read < payload_len {
+            let n = try!(input.read(&mut payload[read..]));
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                           "unexpected EOF while reading frame payload"));
+            }
+            read += n;
+        }
+
         Ok(payload)
     }
 
@@ -172,3 +342,104 @@ impl WebSocketFrame {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // A `Write` that only accepts one byte per call, to prove `write`
+    // drives every call through to completion instead of stopping after one
+    // short write (as a bare `output.write(...)` would).
+    struct OneByteAtATimeWriter {
+        written: Vec<u8>
+    }
+
+    impl Write for OneByteAtATimeWriter {
+        fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+            let n = std::cmp::min(1, buf.len());
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> IOResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_completes_masked_frames_over_a_short_writer() {
+        let frame = WebSocketFrame::close(CloseCode::Normal, "bye", Role::Client);
+        let mut writer = OneByteAtATimeWriter { written: Vec::new() };
+        frame.write(&mut writer).unwrap();
+
+        let parsed = WebSocketFrame::read(&mut Cursor::new(writer.written), DEFAULT_MAX_PAYLOAD_LEN, Role::Server).unwrap();
+        assert_eq!(&parsed.payload[2..], b"bye");
+    }
+
+    #[test]
+    fn write_completes_unmasked_frames_over_a_short_writer() {
+        let frame = WebSocketFrame::text("hello", Role::Server);
+        let mut writer = OneByteAtATimeWriter { written: Vec::new() };
+        frame.write(&mut writer).unwrap();
+
+        let parsed = WebSocketFrame::read(&mut Cursor::new(writer.written), DEFAULT_MAX_PAYLOAD_LEN, Role::Client).unwrap();
+        assert_eq!(&parsed.payload[..], b"hello");
+    }
+
+    #[test]
+    fn reserved_bits_are_rejected() {
+        let frame = WebSocketFrame::text("hi", Role::Client);
+        let mut bytes = Vec::new();
+        frame.write(&mut bytes).unwrap();
+        bytes[0] |= 0x40; // set RSV1
+
+        match WebSocketFrame::read(&mut Cursor::new(bytes), DEFAULT_MAX_PAYLOAD_LEN, Role::Server) {
+            Err(FrameError::ReservedBitsSet) => {},
+            other => panic!("expected ReservedBitsSet, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn oversized_payload_is_rejected_with_message_too_big() {
+        let frame = WebSocketFrame::binary(&[0u8; 10], Role::Client);
+        let mut bytes = Vec::new();
+        frame.write(&mut bytes).unwrap();
+
+        match WebSocketFrame::read(&mut Cursor::new(bytes), 5, Role::Server) {
+            Err(e @ FrameError::PayloadTooLarge) => assert_eq!(e.close_code(), CloseCode::MessageTooBig),
+            other => panic!("expected PayloadTooLarge, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn other_frame_errors_close_with_protocol_error() {
+        assert_eq!(FrameError::MaskMismatch.close_code(), CloseCode::ProtocolError);
+        assert_eq!(FrameError::ReservedBitsSet.close_code(), CloseCode::ProtocolError);
+    }
+
+    #[test]
+    fn close_with_code_echoes_an_arbitrary_status_code() {
+        let frame = WebSocketFrame::close_with_code(4000, "bye", Role::Client);
+        let mut bytes = Vec::new();
+        frame.write(&mut bytes).unwrap();
+
+        let parsed = WebSocketFrame::read(&mut Cursor::new(bytes), DEFAULT_MAX_PAYLOAD_LEN, Role::Server).unwrap();
+        let code = ((parsed.payload[0] as u16) << 8) | (parsed.payload[1] as u16);
+        assert_eq!(code, 4000);
+        assert_eq!(&parsed.payload[2..], b"bye");
+    }
+
+    #[test]
+    fn close_code_validity_ranges() {
+        assert!(CloseCode::is_valid(1000));
+        assert!(CloseCode::is_valid(1011));
+        assert!(CloseCode::is_valid(3000));
+        assert!(CloseCode::is_valid(4999));
+        assert!(!CloseCode::is_valid(1004));
+        assert!(!CloseCode::is_valid(1005));
+        assert!(!CloseCode::is_valid(1006));
+        assert!(!CloseCode::is_valid(1015));
+        assert!(!CloseCode::is_valid(5000));
+    }
+}
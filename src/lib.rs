@@ -0,0 +1,5 @@
+extern crate byteorder;
+extern crate rand;
+
+pub mod frame;
+pub mod message;
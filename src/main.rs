@@ -2,18 +2,59 @@ extern crate mio;
 extern crate http_muncher;
 extern crate sha1;
 extern crate rustc_serialize;
+extern crate rust_chat;
 
 use rustc_serialize::base64::{ToBase64, STANDARD};
 use http_muncher::{Parser, ParserHandler};
 use mio::*;
+use std::io;
+use std::io::Cursor;
 use std::net::SocketAddr;
 use std::collections::HashMap;
 use mio::tcp::*;
 use std::cell::RefCell;
 use std::rc::Rc;
 
+use rust_chat::frame::{CloseCode, FrameError, Role, WebSocketFrame, DEFAULT_MAX_PAYLOAD_LEN};
+use rust_chat::message::{Message, MessageReassembler};
+
+// Accumulates raw bytes received across `ready` callbacks, since a frame
+// header or payload can straddle two edge-triggered reads, or a payload can
+// be larger than a single `try_read`'s buffer.
+struct ByteBuffer {
+  data: Vec<u8>
+}
+
+impl ByteBuffer {
+  fn new() -> ByteBuffer {
+    ByteBuffer { data: Vec::new() }
+  }
+
+  fn extend(&mut self, bytes: &[u8]) {
+    self.data.extend_from_slice(bytes);
+  }
+
+  // Attempts to parse one complete frame out of the buffered bytes,
+  // consuming exactly the bytes it used. Returns `Ok(None)` when there isn't
+  // yet enough data for a full frame, leaving the buffer untouched so the
+  // next call can pick up where this one left off.
+  fn take_frame(&mut self, max_payload_len: usize, role: Role) -> Result<Option<WebSocketFrame>, FrameError> {
+    let mut cursor = Cursor::new(&self.data[..]);
+
+    match WebSocketFrame::read(&mut cursor, max_payload_len, role) {
+      Ok(frame) => {
+        let consumed = cursor.position() as usize;
+        self.data.drain(0..consumed);
+        Ok(Some(frame))
+      },
+      Err(FrameError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+      Err(e) => Err(e)
+    }
+  }
+}
+
 fn gen_key(key: &String) -> String {
-  let mut m = sha1::Sha1::new()
+  let mut m = sha1::Sha1::new();
   let mut buf = [0u8; 20];
 
   m.update(key.as_bytes());
@@ -32,11 +73,12 @@ struct HttpParser {
 impl ParserHandler for HttpParser {
   fn on_header_field(&mut self, s:&[u8]) -> bool {
     self.current_key = Some(std::str::from_utf8(s).unwrap().to_string());
+    true
   }
 
   fn on_header_value(&mut self, s:&[u8]) -> bool {
     self.headers.borrow_mut()
-      .insert(self.current_key.clone(.unwrap(),
+      .insert(self.current_key.clone().unwrap(),
             std::str::from_utf8(s).unwrap().to_string());
     true
   }
@@ -46,18 +88,45 @@ impl ParserHandler for HttpParser {
   }
 }
 
+// Tracks where a client sits in the WebSocket upgrade process. Clients start
+// out as plain HTTP connections and only become WebSocket connections once
+// the handshake response has been written back in full.
+#[derive(Debug, PartialEq)]
+enum ClientState {
+  AwaitingHandshake,
+  HandshakeResponse,
+  Connected
+}
+
 struct WebSocketClient {
   socket: TcpStream,
-  http_parser: Parser<HttpParser>
+  http_parser: Parser<HttpParser>,
+  headers: Rc<RefCell<HashMap<String, String>>>,
+  interest: EventSet,
+  state: ClientState,
+  // Bytes of the handshake response still waiting to be flushed.
+  outgoing: Vec<u8>,
+  reassembler: MessageReassembler,
+  // Set once we've sent our own close frame, so we never send a second one.
+  closing: bool,
+  max_payload_len: usize,
+  incoming: ByteBuffer
 }
 
 impl WebSocketClient {
-  fn read(&mut self) {
+  fn read(&mut self, event_loop: &mut EventLoop<WebSocketServer>, token: Token) {
+    match self.state {
+      ClientState::Connected => self.read_frames(event_loop, token),
+      _ => self.read_handshake(event_loop, token)
+    }
+  }
+
+  fn read_handshake(&mut self, event_loop: &mut EventLoop<WebSocketServer>, token: Token) {
     loop {
       let mut buf = [0; 2048];
       match self.socket.try_read(&mut buf) {
         Err(e) => {
-          println("Error while reading socket: {:?}", e);
+          println!("Error while reading socket: {:?}", e);
           return
         },
         Ok(None) =>
@@ -65,26 +134,184 @@ impl WebSocketClient {
         Ok(Some(len)) => {
           self.http_parser.parse(&buf[0..len]);
           if self.http_parser.is_upgrade() {
-          // ...
+             self.state = ClientState::HandshakeResponse;
              break;
           }
         }
       }
     }
+
+    if self.state == ClientState::HandshakeResponse {
+      match self.handshake_response() {
+        Some(response) => {
+          self.outgoing = response;
+          self.interest.remove(EventSet::readable());
+          self.interest.insert(EventSet::writable());
+        },
+        None => {
+          println!("Handshake request is missing Sec-WebSocket-Key");
+          event_loop.deregister(&self.socket).unwrap();
+          self.closing = true;
+          return;
+        }
+      }
+    }
+
+    event_loop.reregister(&self.socket, token, self.interest,
+                 PollOpt::edge() | PollOpt::oneshot()).unwrap();
   }
 
-  fn new(socket: TcpStream) -> WebSocketClient {
+  fn read_frames(&mut self, event_loop: &mut EventLoop<WebSocketServer>, token: Token) {
+    loop {
+      let mut buf = [0; 2048];
+      match self.socket.try_read(&mut buf) {
+        Err(e) => {
+          println!("Error while reading socket: {:?}", e);
+          return
+        },
+        Ok(None) =>
+          break,
+        Ok(Some(len)) =>
+          self.incoming.extend(&buf[0..len])
+      }
+    }
+
+    loop {
+      match self.incoming.take_frame(self.max_payload_len, Role::Server) {
+        Ok(Some(frame)) => {
+          if !self.handle_frame(frame, event_loop, token) {
+            return;
+          }
+        },
+        Ok(None) => break,
+        Err(e) => {
+          println!("Error while reading frame: {:?}", e);
+          let code = e.close_code();
+          self.close(code, "", event_loop, token);
+          return;
+        }
+      }
+    }
+
+    event_loop.reregister(&self.socket, token, self.interest,
+                 PollOpt::edge() | PollOpt::oneshot()).unwrap();
+  }
+
+  // Feeds a parsed frame through the reassembler and reacts to the message
+  // it completes, if any. Returns `false` once the connection is closing.
+  fn handle_frame(&mut self, frame: WebSocketFrame, event_loop: &mut EventLoop<WebSocketServer>, token: Token) -> bool {
+    match self.reassembler.add(frame) {
+      // Echo back the status code the peer sent us, per RFC 6455 section
+      // 7.1.5; a close with no payload at all gets a plain Normal reply.
+      Ok(Some(Message::Close(None))) => {
+        self.close(CloseCode::Normal, "", event_loop, token);
+        false
+      },
+      Ok(Some(Message::Close(Some((code, _reason))))) => {
+        self.close_raw(code, "", event_loop, token);
+        false
+      },
+      Ok(Some(_message)) => true,
+      Ok(None) => true,
+      Err(e) => {
+        println!("Protocol error while reassembling message: {:?}", e);
+        self.close(CloseCode::ProtocolError, "", event_loop, token);
+        false
+      }
+    }
+  }
+
+  // Sends a close frame (at most once) and deregisters the client. Used both
+  // for a normal close handshake and to abort on a protocol error.
+  fn close(&mut self, code: CloseCode, reason: &str, event_loop: &mut EventLoop<WebSocketServer>, token: Token) {
+    self.close_raw(code as u16, reason, event_loop, token);
+  }
+
+  // Like `close`, but takes a raw status code so a code we received from the
+  // peer (rather than one of ours) can be echoed straight back.
+  fn close_raw(&mut self, code: u16, reason: &str, event_loop: &mut EventLoop<WebSocketServer>, token: Token) {
+    if self.closing {
+      return;
+    }
+    self.closing = true;
+
+    let mut payload = Vec::new();
+    WebSocketFrame::close_with_code(code, reason, Role::Server).write(&mut payload).unwrap();
+
+    if let Err(e) = self.socket.try_write(&payload) {
+      println!("Error while writing close frame: {:?}", e);
+    }
+
+    event_loop.deregister(&self.socket).unwrap();
+  }
+
+  fn write(&mut self, event_loop: &mut EventLoop<WebSocketServer>, token: Token) {
+    match self.socket.try_write(&self.outgoing) {
+      Err(e) => {
+        println!("Error while writing handshake response: {:?}", e);
+        return
+      },
+      Ok(None) => {
+        // Socket isn't ready for writing yet; try again on the next event.
+      },
+      Ok(Some(len)) => {
+        self.outgoing.drain(0..len);
+
+        if self.outgoing.is_empty() {
+          self.state = ClientState::Connected;
+          self.interest.remove(EventSet::writable());
+          self.interest.insert(EventSet::readable());
+        }
+      }
+    }
+
+    event_loop.reregister(&self.socket, token, self.interest,
+                 PollOpt::edge() | PollOpt::oneshot()).unwrap();
+  }
+
+  // Returns `None` if the upgrade request never supplied a
+  // `Sec-WebSocket-Key`; `is_upgrade()` alone doesn't guarantee it's there.
+  fn handshake_response(&self) -> Option<Vec<u8>> {
+    let headers = self.headers.borrow();
+    let request_key = match headers.get("Sec-WebSocket-Key") {
+      Some(key) => key,
+      None => return None
+    };
+    let response_key = gen_key(request_key);
+
+    Some(format!("HTTP/1.1 101 Switching Protocols\r\n\
+             Connection: Upgrade\r\n\
+             Upgrade: websocket\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n", response_key).into_bytes())
+  }
+
+  fn new(socket: TcpStream, max_payload_len: usize) -> WebSocketClient {
+    let headers = Rc::new(RefCell::new(HashMap::new()));
+
     WebSocketClient {
       socket: socket,
-      http_parser: Parser::request(HttpParser)
+      headers: headers.clone(),
+      http_parser: Parser::request(HttpParser {
+        current_key: None,
+        headers: headers
+      }),
+      interest: EventSet::readable(),
+      state: ClientState::AwaitingHandshake,
+      outgoing: Vec::new(),
+      reassembler: MessageReassembler::new(),
+      closing: false,
+      max_payload_len: max_payload_len,
+      incoming: ByteBuffer::new()
     }
   }
 }
 
 struct WebSocketServer {
   socket: TcpListener,
-  clients: HashMap<Token, TcpStream>,
-  token_counter: usize
+  clients: HashMap<Token, WebSocketClient>,
+  token_counter: usize,
+  // Caps a single frame's advertised payload length; configurable per server.
+  max_payload_len: usize
 }
 
 const SERVER_TOKEN: Token = Token(0);
@@ -102,22 +329,31 @@ impl Handler for WebSocketServer {
                   return;
               },
               Ok(None) => unreachable!("Accept has returned 'None'"),
-              Ok(Some((sock, addr))) => sock
+              Ok(Some((sock, _addr))) => sock
             };
 
             self.token_counter += 1;
             let new_token = Token(self.token_counter);
 
-            self.clients.insert(new_token, WebSocketClient::new(client_socket));
+            self.clients.insert(new_token, WebSocketClient::new(client_socket, self.max_payload_len));
             event_loop.register(&self.clients[&new_token].socket, new_token, EventSet::readable(),
                         PollOpt::edge() | PollOpt::oneshot()).unwrap();
 
           },
           token => {
-            let mut client = self.clients.get_mut(&otken).unwrap();
-            client.read();
-            event_loop.register(&client.socket, token, EventSet::readable(),
-                          PollOpt::edge() | PollOpt::oneshot().unwrap();
+            if events.is_writable() {
+              let mut client = self.clients.get_mut(&token).unwrap();
+              client.write(event_loop, token);
+            }
+
+            if events.is_readable() {
+              let mut client = self.clients.get_mut(&token).unwrap();
+              client.read(event_loop, token);
+            }
+
+            if self.clients.get(&token).map_or(false, |client| client.closing) {
+              self.clients.remove(&token);
+            }
           }
         }
     }
@@ -130,7 +366,8 @@ fn main() {
   let mut server = WebSocketServer{
     token_counter: 1,
     clients: HashMap::new(),
-    socket: server_socket
+    socket: server_socket,
+    max_payload_len: DEFAULT_MAX_PAYLOAD_LEN
   };
 
   event_loop.register(&server.socket,
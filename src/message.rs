@@ -0,0 +1,234 @@
+use frame::{CloseCode, OpCode, WebSocketFrame};
+
+// A fully reassembled WebSocket message, after stitching together any
+// fragmented frames that made it up.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<(u16, String)>)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MessageError {
+    UnknownOpcode,
+    UnexpectedContinuation,
+    // A new data frame arrived while a fragmented message was still open.
+    // Per RFC 6455 section 5.4, frames from different messages must never
+    // be interleaved.
+    InterleavedDataFrame,
+    FragmentedControlFrame,
+    ControlFrameTooLarge,
+    InvalidClosePayload,
+    // The close code wasn't in any range RFC 6455 section 7.4 allows an
+    // endpoint to send (e.g. a reserved code like 1005, or one above 4999).
+    InvalidCloseCode,
+    InvalidUtf8
+}
+
+struct Fragment {
+    opcode: OpCode,
+    payload: Vec<u8>
+}
+
+// Accumulates a client's data frames across calls until a `fin` frame
+// completes a `Message`. One of these is kept per connected client.
+pub struct MessageReassembler {
+    fragment: Option<Fragment>
+}
+
+impl MessageReassembler {
+    pub fn new() -> MessageReassembler {
+        MessageReassembler { fragment: None }
+    }
+
+    // Feeds a single parsed frame in and returns the `Message` it completed,
+    // if any. Returns `Ok(None)` while a fragmented message is still being
+    // assembled.
+    pub fn add(&mut self, frame: WebSocketFrame) -> Result<Option<Message>, MessageError> {
+        let opcode = try!(frame.opcode().ok_or(MessageError::UnknownOpcode));
+
+        if opcode.is_control() {
+            if !frame.is_fin() {
+                return Err(MessageError::FragmentedControlFrame);
+            }
+            if frame.payload.len() > 125 {
+                return Err(MessageError::ControlFrameTooLarge);
+            }
+            return Self::to_message(opcode, frame.payload).map(Some);
+        }
+
+        if let OpCode::Continuation = opcode {
+            let mut fragment = match self.fragment.take() {
+                Some(fragment) => fragment,
+                None => return Err(MessageError::UnexpectedContinuation)
+            };
+
+            fragment.payload.extend_from_slice(&frame.payload);
+
+            if frame.is_fin() {
+                Self::to_message(fragment.opcode, fragment.payload).map(Some)
+            } else {
+                self.fragment = Some(fragment);
+                Ok(None)
+            }
+        } else if self.fragment.is_some() {
+            Err(MessageError::InterleavedDataFrame)
+        } else if frame.is_fin() {
+            Self::to_message(opcode, frame.payload).map(Some)
+        } else {
+            self.fragment = Some(Fragment { opcode: opcode, payload: frame.payload });
+            Ok(None)
+        }
+    }
+
+    fn to_message(opcode: OpCode, payload: Vec<u8>) -> Result<Message, MessageError> {
+        match opcode {
+            OpCode::TextFrame =>
+                String::from_utf8(payload)
+                    .map(Message::Text)
+                    .map_err(|_| MessageError::InvalidUtf8),
+            OpCode::BinaryFrame => Ok(Message::Binary(payload)),
+            OpCode::Ping => Ok(Message::Ping(payload)),
+            OpCode::Pong => Ok(Message::Pong(payload)),
+            OpCode::ConnectionClose => Self::to_close_message(payload),
+            OpCode::Continuation =>
+                unreachable!("continuation frames are reassembled before reaching to_message")
+        }
+    }
+
+    fn to_close_message(payload: Vec<u8>) -> Result<Message, MessageError> {
+        if payload.is_empty() {
+            return Ok(Message::Close(None));
+        }
+        if payload.len() < 2 {
+            return Err(MessageError::InvalidClosePayload);
+        }
+
+        let code = ((payload[0] as u16) << 8) | (payload[1] as u16);
+        if !CloseCode::is_valid(code) {
+            return Err(MessageError::InvalidCloseCode);
+        }
+        let reason = try!(String::from_utf8(payload[2..].to_vec())
+            .map_err(|_| MessageError::InvalidUtf8));
+
+        Ok(Message::Close(Some((code, reason))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use frame::{Role, WebSocketFrame, DEFAULT_MAX_PAYLOAD_LEN};
+
+    use byteorder::{BigEndian, WriteBytesExt};
+    use std::io::Cursor;
+
+    // Hand-builds the wire bytes for a single frame, since `WebSocketFrame`'s
+    // public constructors can't produce fragmented/continuation frames.
+    // Always masked, since these are parsed as server-received frames.
+    fn raw_frame_bytes(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.push(((fin as u8) << 7) | (opcode & 0x0F));
+
+        let len = payload.len();
+        if len < 126 {
+            bytes.push(0x80 | (len as u8));
+        } else {
+            bytes.push(0x80 | 126);
+            bytes.write_u16::<BigEndian>(len as u16).unwrap();
+        }
+
+        let mask = [1u8, 2, 3, 4];
+        bytes.extend_from_slice(&mask);
+        let mut masked_payload = payload.to_vec();
+        for (i, b) in masked_payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+        bytes.extend_from_slice(&masked_payload);
+
+        bytes
+    }
+
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> WebSocketFrame {
+        let bytes = raw_frame_bytes(fin, opcode, payload);
+        WebSocketFrame::read(&mut Cursor::new(bytes), DEFAULT_MAX_PAYLOAD_LEN, Role::Server).unwrap()
+    }
+
+    #[test]
+    fn unfragmented_text_completes_immediately() {
+        let mut r = MessageReassembler::new();
+        assert_eq!(r.add(frame(true, 1, b"hello")), Ok(Some(Message::Text("hello".to_string()))));
+    }
+
+    #[test]
+    fn invalid_utf8_text_is_rejected() {
+        let mut r = MessageReassembler::new();
+        assert_eq!(r.add(frame(true, 1, &[0xff, 0xff])), Err(MessageError::InvalidUtf8));
+    }
+
+    #[test]
+    fn fragmented_text_reassembles_across_continuations() {
+        let mut r = MessageReassembler::new();
+        assert_eq!(r.add(frame(false, 1, b"hel")), Ok(None));
+        assert_eq!(r.add(frame(true, 0, b"lo")), Ok(Some(Message::Text("hello".to_string()))));
+    }
+
+    #[test]
+    fn continuation_without_a_preceding_frame_is_an_error() {
+        let mut r = MessageReassembler::new();
+        assert_eq!(r.add(frame(true, 0, b"lo")), Err(MessageError::UnexpectedContinuation));
+    }
+
+    #[test]
+    fn data_frame_interleaved_into_an_open_fragment_is_an_error() {
+        let mut r = MessageReassembler::new();
+        assert_eq!(r.add(frame(false, 1, b"hel")), Ok(None));
+        assert_eq!(r.add(frame(true, 2, b"oops")), Err(MessageError::InterleavedDataFrame));
+    }
+
+    #[test]
+    fn fragmented_control_frame_is_an_error() {
+        let mut r = MessageReassembler::new();
+        assert_eq!(r.add(frame(false, 9, b"ping")), Err(MessageError::FragmentedControlFrame));
+    }
+
+    #[test]
+    fn oversized_control_frame_is_an_error() {
+        let mut r = MessageReassembler::new();
+        let payload = vec![0u8; 126];
+        assert_eq!(r.add(frame(true, 9, &payload)), Err(MessageError::ControlFrameTooLarge));
+    }
+
+    #[test]
+    fn close_payload_round_trips_code_and_reason() {
+        let mut payload = Vec::new();
+        payload.write_u16::<BigEndian>(1000).unwrap();
+        payload.extend_from_slice(b"bye");
+
+        let mut r = MessageReassembler::new();
+        assert_eq!(r.add(frame(true, 8, &payload)),
+                   Ok(Some(Message::Close(Some((1000, "bye".to_string()))))));
+    }
+
+    #[test]
+    fn close_payload_preserves_a_non_default_code_for_echoing_back() {
+        let mut payload = Vec::new();
+        payload.write_u16::<BigEndian>(4000).unwrap();
+
+        let mut r = MessageReassembler::new();
+        assert_eq!(r.add(frame(true, 8, &payload)),
+                   Ok(Some(Message::Close(Some((4000, "".to_string()))))));
+    }
+
+    #[test]
+    fn close_payload_with_a_reserved_code_is_rejected() {
+        let mut payload = Vec::new();
+        payload.write_u16::<BigEndian>(1005).unwrap(); // reserved, never sent on the wire
+
+        let mut r = MessageReassembler::new();
+        assert_eq!(r.add(frame(true, 8, &payload)), Err(MessageError::InvalidCloseCode));
+    }
+}